@@ -1,20 +1,523 @@
 use log::{debug, error, info, warn};
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Notify;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::time::{self, Duration};
+use tokio_kcp::{KcpListener, KcpStream};
+use tokio_util::sync::CancellationToken;
 
 const MAX_RETRIES: u32 = 5;
 const RETRY_DELAY: Duration = Duration::from_secs(1);
 
+/// Socket and connect-retry tuning for a proxy instance.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Timeout applied to each individual backend connect attempt.
+    pub connect_timeout: Duration,
+    /// Number of retries after the first failed connect attempt.
+    pub connect_retries: u32,
+    /// Delay before the first retry; doubles after each subsequent failure.
+    pub connect_backoff: Duration,
+    pub tcp_nodelay: bool,
+    /// `SO_KEEPALIVE` idle time and probe interval; `None` disables keepalive.
+    pub tcp_keepalive: Option<Duration>,
+    /// `SO_LINGER`; `None` leaves the OS default in place.
+    pub tcp_linger: Option<Duration>,
+    /// How long to let in-flight connections finish on shutdown before
+    /// they're force-aborted.
+    pub drain_deadline: Duration,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            connect_timeout: Duration::from_secs(5),
+            connect_retries: 3,
+            connect_backoff: Duration::from_millis(200),
+            tcp_nodelay: true,
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            tcp_linger: None,
+            drain_deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+fn apply_tcp_tuning(stream: &TcpStream, config: &ProxyConfig) -> io::Result<()> {
+    stream.set_nodelay(config.tcp_nodelay)?;
+
+    let sock_ref = socket2::SockRef::from(stream);
+
+    if let Some(keepalive_time) = config.tcp_keepalive {
+        let keepalive = socket2::TcpKeepalive::new()
+            .with_time(keepalive_time)
+            .with_interval(keepalive_time);
+        sock_ref.set_tcp_keepalive(&keepalive)?;
+    }
+
+    sock_ref.set_linger(config.tcp_linger)?;
+
+    Ok(())
+}
+
+/// Applies socket tuning where it's meaningful; only TCP streams carry the
+/// relevant socket options, so other transports are left untouched.
+fn apply_socket_tuning(stream: &AnyStream, config: &ProxyConfig) -> io::Result<()> {
+    if let AnyStream::Tcp(tcp) = stream {
+        apply_tcp_tuning(tcp, config)?;
+    }
+    Ok(())
+}
+
+/// Dials `endpoint`, retrying on connect failure or per-attempt timeout
+/// with exponential backoff, so a backend that is transiently unavailable
+/// (e.g. a pod restarting) doesn't instantly fail the client.
+async fn connect_with_retry(endpoint: &Endpoint, config: &ProxyConfig) -> io::Result<AnyStream> {
+    let mut attempt = 0;
+    let mut backoff = config.connect_backoff;
+
+    loop {
+        match time::timeout(config.connect_timeout, endpoint.connect()).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) if attempt < config.connect_retries => {
+                warn!(
+                    "Failed to connect to {} (attempt {}/{}): {}. Retrying in {:?}...",
+                    endpoint,
+                    attempt + 1,
+                    config.connect_retries + 1,
+                    e,
+                    backoff
+                );
+                attempt += 1;
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Ok(Err(e)) => {
+                error!(
+                    "Failed to connect to {} after {} attempts: {}.",
+                    endpoint,
+                    attempt + 1,
+                    e
+                );
+                return Err(e);
+            }
+            Err(_) if attempt < config.connect_retries => {
+                warn!(
+                    "Timed out connecting to {} after {:?} (attempt {}/{}). Retrying in {:?}...",
+                    endpoint,
+                    config.connect_timeout,
+                    attempt + 1,
+                    config.connect_retries + 1,
+                    backoff
+                );
+                attempt += 1;
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(_) => {
+                error!(
+                    "Timed out connecting to {} after {} attempts.",
+                    endpoint,
+                    attempt + 1
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connect to {} timed out", endpoint),
+                ));
+            }
+        }
+    }
+}
+
+/// Tuning knobs for the KCP (reliable, ordered delivery over UDP)
+/// transport, mirrored from `tokio_kcp`'s `KcpConfig` / `KcpNoDelayConfig`
+/// so callers don't need to depend on `tokio_kcp` directly.
+#[derive(Debug, Clone)]
+pub struct KcpConfig {
+    pub mtu: usize,
+    pub nodelay: bool,
+    pub interval: i32,
+    pub resend: i32,
+    pub flow_control: bool,
+    pub send_window_size: u16,
+    pub recv_window_size: u16,
+}
+
+impl Default for KcpConfig {
+    fn default() -> Self {
+        KcpConfig {
+            mtu: 1400,
+            nodelay: true,
+            interval: 10,
+            resend: 2,
+            flow_control: false,
+            send_window_size: 256,
+            recv_window_size: 256,
+        }
+    }
+}
+
+impl KcpConfig {
+    fn to_tokio_kcp(&self) -> tokio_kcp::KcpConfig {
+        tokio_kcp::KcpConfig {
+            mtu: self.mtu,
+            nodelay: tokio_kcp::KcpNoDelayConfig {
+                nodelay: self.nodelay,
+                interval: self.interval,
+                resend: self.resend,
+                nc: !self.flow_control,
+            },
+            wnd_size: (self.send_window_size, self.recv_window_size),
+            ..Default::default()
+        }
+    }
+}
+
+fn kcp_err_to_io(e: tokio_kcp::KcpError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// A transport-neutral description of where to listen or where to dial,
+/// so the proxy can front a TCP address, a Unix domain socket, or a KCP
+/// session over UDP.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Kcp {
+        host: String,
+        port: u16,
+        config: KcpConfig,
+    },
+}
+
+impl Endpoint {
+    pub async fn connect(&self) -> io::Result<AnyStream> {
+        match self {
+            Endpoint::Tcp { host, port } => {
+                let stream = TcpStream::connect((host.as_str(), *port)).await?;
+                Ok(AnyStream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let stream = UnixStream::connect(path).await?;
+                Ok(AnyStream::Unix(stream))
+            }
+            Endpoint::Kcp { host, port, config } => {
+                let addr = resolve_udp_addr(host, *port)?;
+                let stream = KcpStream::connect(&config.to_tokio_kcp(), addr)
+                    .await
+                    .map_err(kcp_err_to_io)?;
+                Ok(AnyStream::Kcp(stream))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Kcp { host, port, .. } => write!(f, "kcp:{}:{}", host, port),
+        }
+    }
+}
+
+fn resolve_udp_addr(host: &str, port: u16) -> io::Result<std::net::SocketAddr> {
+    format!("{}:{}", host, port).parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid KCP address: {e}"),
+        )
+    })
+}
+
+/// Either side of a forwarded connection, abstracted over TCP, Unix domain
+/// sockets and KCP so `handle_client` only needs `AsyncRead + AsyncWrite`.
+pub enum AnyStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Kcp(KcpStream),
+}
+
+impl io::AsyncRead for AnyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AnyStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            AnyStream::Kcp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl io::AsyncWrite for AnyStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AnyStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            AnyStream::Kcp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AnyStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            AnyStream::Kcp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AnyStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            AnyStream::Kcp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The accept side of [`Endpoint`]: a listener bound to a TCP address, a
+/// Unix domain socket path, or a KCP session over UDP.
+pub enum ProxyListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    Kcp(KcpListener),
+}
+
+impl ProxyListener {
+    pub async fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp { host, port } => {
+                let listener = TcpListener::bind((host.as_str(), *port)).await?;
+                Ok(ProxyListener::Tcp(listener))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                // Binding to a stale socket file left behind by a previous
+                // run fails with `AddrInUse`, so clear it first.
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                Ok(ProxyListener::Unix(listener))
+            }
+            Endpoint::Kcp { host, port, config } => {
+                let addr = resolve_udp_addr(host, *port)?;
+                let listener = KcpListener::bind(config.to_tokio_kcp(), addr)
+                    .await
+                    .map_err(kcp_err_to_io)?;
+                Ok(ProxyListener::Kcp(listener))
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(AnyStream, String)> {
+        match self {
+            ProxyListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((AnyStream::Tcp(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            ProxyListener::Unix(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                let addr = addr
+                    .as_pathname()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| "<unnamed unix socket>".to_string());
+                Ok((AnyStream::Unix(stream), addr))
+            }
+            ProxyListener::Kcp(listener) => {
+                let (stream, addr) = listener.accept().await.map_err(kcp_err_to_io)?;
+                Ok((AnyStream::Kcp(stream), addr.to_string()))
+            }
+        }
+    }
+}
+
+/// A per-connection backend selector driven by the client's HTTP `Host`
+/// header (plaintext) or TLS ClientHello SNI extension (encrypted), with a
+/// fallback `Endpoint` for hosts that don't match any route. Both the
+/// `Host` header (RFC 7230 §5.4) and SNI `server_name` (RFC 6066 §3) are
+/// matched case-insensitively.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: HashMap<String, Endpoint>,
+    default: Option<Endpoint>,
+}
+
+impl Router {
+    pub fn new(default: Endpoint) -> Self {
+        Router {
+            routes: HashMap::new(),
+            default: Some(default),
+        }
+    }
+
+    pub fn with_route(mut self, host: impl Into<String>, endpoint: Endpoint) -> Self {
+        self.routes
+            .insert(host.into().to_ascii_lowercase(), endpoint);
+        self
+    }
+
+    fn resolve(&self, host: Option<&str>) -> Option<&Endpoint> {
+        host.and_then(|h| self.routes.get(&h.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+    }
+}
+
+/// Caps how much of a connection's opening bytes we'll buffer while
+/// sniffing, so a client that never completes its ClientHello/request
+/// can't make us hold an unbounded amount of memory.
+const MAX_SNIFF_BYTES: usize = 16 * 1024;
+
+/// How long to wait for more bytes once sniffing has something to work
+/// with but hasn't yet seen a complete TLS record or HTTP header block.
+const SNIFF_IDLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Reads from `stream` until a complete TLS ClientHello record or a
+/// complete HTTP request line + header block has accumulated, so the
+/// caller can reliably sniff a `Host`/SNI value even when the client's
+/// TLS or HTTP stack splits it across more than one `read()`. Gives up
+/// once `MAX_SNIFF_BYTES` is buffered or no further bytes arrive within
+/// `SNIFF_IDLE_TIMEOUT`; the caller is responsible for replaying
+/// whatever was read to the chosen backend verbatim.
+async fn read_initial_bytes(stream: &mut AnyStream) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    while !is_complete_for_sniffing(&buf) && buf.len() < MAX_SNIFF_BYTES {
+        let mut chunk = [0u8; 4096];
+
+        let read = if buf.is_empty() {
+            // Block on the very first read so we don't busy-loop on a
+            // connection that simply hasn't sent anything yet.
+            stream.read(&mut chunk).await?
+        } else {
+            match time::timeout(SNIFF_IDLE_TIMEOUT, stream.read(&mut chunk)).await {
+                Ok(result) => result?,
+                Err(_) => break, // no more bytes in time; sniff what we have
+            }
+        };
+
+        if read == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+
+    Ok(buf)
+}
+
+/// Whether `buf` holds enough of a connection's opening bytes to sniff a
+/// hostname from: a full TLS record per its 5-byte header's length field,
+/// or an HTTP request with a terminated header block.
+fn is_complete_for_sniffing(buf: &[u8]) -> bool {
+    if buf.first() == Some(&0x16) {
+        if buf.len() < 5 {
+            return false;
+        }
+        let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        buf.len() >= 5 + record_len
+    } else {
+        buf.windows(4).any(|w| w == b"\r\n\r\n")
+    }
+}
+
+/// Extracts the requested hostname from the first bytes of a client
+/// connection: the HTTP `Host:` header for plaintext requests, or the SNI
+/// `server_name` extension from a TLS ClientHello.
+fn sniff_host(buf: &[u8]) -> Option<String> {
+    if buf.first() == Some(&0x16) {
+        parse_sni_host(buf)
+    } else {
+        parse_http_host(buf)
+    }
+}
+
+fn parse_http_host(buf: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(buf).ok()?;
+    text.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.eq_ignore_ascii_case("host") {
+            Some(value.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Walks a TLS ClientHello to find the `server_name` extension: record
+/// header (5 bytes), handshake header (4 bytes), then the ClientHello body
+/// (version, random, session id, cipher suites, compression methods)
+/// before reaching the extensions block.
+fn parse_sni_host(buf: &[u8]) -> Option<String> {
+    let mut pos = 5; // TLS record header: content-type + version + length
+    pos += 4; // Handshake header: msg-type + length
+    pos += 2 + 32; // ClientHello: client_version + random
+
+    let session_id_len = *buf.get(pos)? as usize;
+    pos += 1 + session_id_len;
+
+    let cipher_suites_len = u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?) as usize;
+    pos += 2 + cipher_suites_len;
+
+    let compression_methods_len = *buf.get(pos)? as usize;
+    pos += 1 + compression_methods_len;
+
+    let extensions_len = u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?) as usize;
+    pos += 2;
+    let extensions_end = pos.checked_add(extensions_len)?.min(buf.len());
+
+    while pos + 4 <= extensions_end {
+        let ext_type = u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?);
+        let ext_len = u16::from_be_bytes(buf.get(pos + 2..pos + 4)?.try_into().ok()?) as usize;
+        pos += 4;
+
+        if ext_type == 0x0000 {
+            // server_name_list length(2) + entry: type(1) + host_name length(2) + host_name
+            let name_len = u16::from_be_bytes(buf.get(pos + 3..pos + 5)?.try_into().ok()?) as usize;
+            let name_start = pos + 5;
+            let name = buf.get(name_start..name_start + name_len)?;
+            return std::str::from_utf8(name).ok().map(|s| s.to_string());
+        }
+
+        pos += ext_len;
+    }
+
+    None
+}
+
 async fn retryable_write(writer: &mut (impl AsyncWriteExt + Unpin), buf: &[u8]) -> io::Result<()> {
     let mut attempts = 0;
     loop {
         match writer.write_all(buf).await {
             Ok(()) => {
                 info!("Successfully wrote to stream.");
+                return Ok(());
             }
             Err(e) if attempts < MAX_RETRIES => {
                 warn!(
@@ -37,143 +540,257 @@ async fn retryable_write(writer: &mut (impl AsyncWriteExt + Unpin), buf: &[u8])
     }
 }
 
-async fn handle_client(
-    client_stream: TcpStream,
-    server_stream: TcpStream,
-    shutdown_notify: Arc<Notify>,
+/// Pumps bytes from `reader` to `writer` until EOF, then shuts down the
+/// write half of `writer` so the peer observes a half-close instead of the
+/// whole connection being torn down.
+async fn pump_half(
+    mut reader: (impl AsyncReadExt + Unpin),
+    mut writer: (impl AsyncWriteExt + Unpin),
+    direction: &str,
 ) -> io::Result<()> {
-    let (mut client_reader, mut client_writer) = io::split(client_stream);
-    let (mut server_reader, mut server_writer) = io::split(server_stream);
-
-    let client_to_server = tokio::spawn(async move {
-        let mut buf = vec![0; 4096];
-        loop {
-            match client_reader.read(&mut buf).await {
-                Ok(0) => {
-                    info!("Client stream closed; stopping client_to_server loop.");
-                    break;
-                }
-                Ok(n) => {
-                    debug!(
-                        "Read {} bytes from client stream; writing to server stream.",
-                        n
-                    );
-                    retryable_write(&mut server_writer, &buf[..n]).await?;
-                }
-                Err(e) => {
-                    error!("An error occurred while reading from client stream: {}", e);
-                    return Err(e);
-                }
+    let mut buf = vec![0; 4096];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) => {
+                info!(
+                    "{} stream closed; shutting down write half of peer.",
+                    direction
+                );
+                writer.shutdown().await?;
+                return Ok(());
             }
-        }
-        Ok::<(), io::Error>(())
-    });
-
-    let server_to_client = tokio::spawn(async move {
-        let mut buf = vec![0; 4096];
-        loop {
-            match server_reader.read(&mut buf).await {
-                Ok(0) => {
-                    info!("Server stream closed; stopping server_to_client loop.");
-                    break;
-                }
-                Ok(n) => {
-                    debug!(
-                        "Read {} bytes from server stream; writing to client stream.",
-                        n
-                    );
-                    retryable_write(&mut client_writer, &buf[..n]).await?;
-                }
-                Err(e) => {
-                    error!("An error occurred while reading from server stream: {}", e);
-                    return Err(e);
-                }
+            Ok(n) => {
+                debug!("Read {} bytes on {}; writing to peer stream.", n, direction);
+                retryable_write(&mut writer, &buf[..n]).await?;
+            }
+            Err(e) => {
+                error!("An error occurred while reading on {}: {}", direction, e);
+                return Err(e);
             }
         }
-        Ok::<(), io::Error>(())
-    });
+    }
+}
+
+fn flatten_join(
+    result: Result<io::Result<()>, tokio::task::JoinError>,
+    direction: &str,
+) -> io::Result<()> {
+    match result {
+        Ok(Ok(())) => {
+            info!("{} task completed successfully.", direction);
+            Ok(())
+        }
+        Ok(Err(e)) => {
+            error!("{} task encountered an IO error: {}", direction, e);
+            Err(e)
+        }
+        Err(e) => {
+            error!("{} task failed to join: {}", direction, e);
+            Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+        }
+    }
+}
+
+async fn handle_client<C, S>(
+    client_stream: C,
+    server_stream: S,
+    shutdown: CancellationToken,
+    drain_deadline: Duration,
+) -> io::Result<()>
+where
+    C: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    S: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+{
+    let (client_reader, client_writer) = io::split(client_stream);
+    let (server_reader, server_writer) = io::split(server_stream);
+
+    let client_to_server =
+        tokio::spawn(
+            async move { pump_half(client_reader, server_writer, "client_to_server").await },
+        );
+    let server_to_client =
+        tokio::spawn(
+            async move { pump_half(server_reader, client_writer, "server_to_client").await },
+        );
+
+    let client_to_server_abort = client_to_server.abort_handle();
+    let server_to_client_abort = server_to_client.abort_handle();
+
+    // Each direction independently shuts down its peer's write half on
+    // EOF, so neither task finishing early tears down the other; this
+    // future only resolves once both halves have completed the relay.
+    let relay = async move {
+        let (c2s, s2c) = tokio::join!(client_to_server, server_to_client);
+        flatten_join(c2s, "client_to_server").and(flatten_join(s2c, "server_to_client"))
+    };
+    tokio::pin!(relay);
 
     tokio::select! {
-        result = client_to_server => {
-            match result {
-                Ok(Ok(())) => {
-                    info!("client_to_server task completed successfully.");
-                    Ok(())
-                },
-                Ok(Err(e)) => {
-                    error!("client_to_server task encountered an IO error: {}", e);
-                    Err(e)
-                },
-                Err(e) => {
-                    error!("client_to_server task failed to join: {}", e);
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, e))
-                },
-            }
-        },
-        result = server_to_client => {
-            match result {
-                Ok(Ok(())) => {
-                    info!("server_to_client task completed successfully.");
+        result = &mut relay => result,
+        _ = shutdown.cancelled() => {
+            warn!(
+                "Shutdown signal received; draining in-flight relay for up to {:?} before aborting.",
+                drain_deadline
+            );
+            match time::timeout(drain_deadline, &mut relay).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!("Drain deadline elapsed; aborting in-flight copy tasks.");
+                    client_to_server_abort.abort();
+                    server_to_client_abort.abort();
+                    // Let the aborted tasks unwind so their stream halves
+                    // are actually dropped before we return.
+                    let _ = relay.await;
                     Ok(())
-                },
-                Ok(Err(e)) => {
-                    error!("server_to_client task encountered an IO error: {}", e);
-                    Err(e)
-                },
-                Err(e) => {
-                    error!("server_to_client task failed to join: {}", e);
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, e))
-                },
+                }
             }
         },
-        _ = shutdown_notify.notified() => {
-            warn!("Shutdown signal received. Exiting handle_client.");
-            Ok(())
-        },
+    }
+}
+
+/// Sniffs the routed host, dials the chosen backend, replays the sniffed
+/// bytes, and relays the connection until both directions reach EOF; once
+/// `shutdown` fires, the relay gets up to `drain_deadline` to finish before
+/// it is aborted. Runs entirely inside a per-connection task so that a
+/// client which never sends a byte, or a backend that's down and eating
+/// through `connect_with_retry`'s backoff budget, only stalls itself
+/// instead of the shared accept loop.
+async fn handle_connection(
+    mut client_stream: AnyStream,
+    peer_addr: String,
+    router: Router,
+    config: ProxyConfig,
+    shutdown: CancellationToken,
+) {
+    if let Err(e) = apply_socket_tuning(&client_stream, &config) {
+        warn!(
+            "Failed to apply socket tuning to client stream from {}: {}",
+            peer_addr, e
+        );
+    }
+
+    let initial_bytes = match read_initial_bytes(&mut client_stream).await {
+        Ok(buf) => buf,
+        Err(e) => {
+            error!("Failed to read initial bytes from {}: {}", peer_addr, e);
+            return;
+        }
+    };
+    let host = sniff_host(&initial_bytes);
+
+    let target_endpoint = match router.resolve(host.as_deref()) {
+        Some(endpoint) => endpoint.clone(),
+        None => {
+            error!(
+                "No route for host {:?} from {} and no default endpoint configured",
+                host, peer_addr
+            );
+            return;
+        }
+    };
+
+    debug!(
+        "Routing connection from {} to {} (host: {:?})",
+        peer_addr, target_endpoint, host
+    );
+
+    let mut server_stream = match connect_with_retry(&target_endpoint, &config).await {
+        Ok(stream) => {
+            info!("Connected to server at {}", target_endpoint);
+            stream
+        }
+        Err(e) => {
+            error!("Failed to connect to server at {}: {}", target_endpoint, e);
+            return;
+        }
+    };
+
+    if let Err(e) = apply_socket_tuning(&server_stream, &config) {
+        warn!(
+            "Failed to apply socket tuning to server stream for {}: {}",
+            target_endpoint, e
+        );
+    }
+
+    if !initial_bytes.is_empty() {
+        if let Err(e) = retryable_write(&mut server_stream, &initial_bytes).await {
+            error!(
+                "Failed to replay initial bytes to {}: {}",
+                target_endpoint, e
+            );
+            return;
+        }
+    }
+
+    if let Err(e) = handle_client(
+        client_stream,
+        server_stream,
+        shutdown,
+        config.drain_deadline,
+    )
+    .await
+    {
+        error!("Error while handling client: {}", e);
     }
 }
 
 pub async fn start_http_proxy(
-    target_host: &str,
-    target_port: u16,
-    proxy_port: u16,
+    listen_endpoint: Endpoint,
+    router: Router,
+    config: ProxyConfig,
     is_running: Arc<AtomicBool>,
-    shutdown_notify: Arc<Notify>,
+    shutdown: CancellationToken,
 ) -> io::Result<()> {
-    let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", proxy_port)).await?;
+    let listener = ProxyListener::bind(&listen_endpoint).await?;
+
+    info!("HTTP Proxy started on {}", listen_endpoint);
 
-    info!("HTTP Proxy started on port {}", proxy_port);
+    let mut connections = tokio::task::JoinSet::new();
 
     while is_running.load(Ordering::SeqCst) {
-        let (client_stream, peer_addr) = tcp_listener.accept().await?;
+        let (client_stream, peer_addr) = tokio::select! {
+            accept_result = listener.accept() => accept_result?,
+            _ = shutdown.cancelled() => {
+                info!("Shutdown signal received; no longer accepting new connections.");
+                break;
+            }
+        };
 
         info!("Accepted connection from {}", peer_addr);
 
-        let server_stream_result =
-            TcpStream::connect(format!("{}:{}", target_host, target_port)).await;
+        let router = router.clone();
+        let config = config.clone();
+        let shutdown_clone = shutdown.clone();
 
-        let server_stream = match server_stream_result {
-            Ok(stream) => {
-                info!("Connected to server at {}:{}", target_host, target_port);
-                stream
-            }
-            Err(e) => {
-                error!(
-                    "Failed to connect to server at {}:{}: {}",
-                    target_host, target_port, e
-                );
-                continue;
-            }
-        };
+        connections.spawn(handle_connection(
+            client_stream,
+            peer_addr,
+            router,
+            config,
+            shutdown_clone,
+        ));
+    }
 
-        let shutdown_notify_clone = shutdown_notify.clone();
+    info!(
+        "HTTP Proxy draining {} connection task(s) not yet joined (deadline {:?})...",
+        connections.len(),
+        config.drain_deadline
+    );
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(client_stream, server_stream, shutdown_notify_clone).await
-            {
-                error!("Error while handling client: {}", e);
-            }
-        });
+    match time::timeout(config.drain_deadline, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await
+    {
+        Ok(()) => info!("HTTP Proxy drained all in-flight connections."),
+        Err(_) => {
+            warn!(
+                "Drain deadline elapsed with {} connection(s) still in flight; aborting.",
+                connections.len()
+            );
+            connections.abort_all();
+            while connections.join_next().await.is_some() {}
+        }
     }
 
     info!("HTTP Proxy stopped.");
@@ -237,24 +854,32 @@ mod tests {
     async fn test_start_http_proxy() {
         let (echo_port, shutdown_sender) = start_echo_server().await.unwrap();
         let is_running = Arc::new(AtomicBool::new(true));
-        let shutdown_notify = Arc::new(Notify::new());
+        let shutdown = CancellationToken::new();
         let proxy_port = {
             let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
             listener.local_addr().unwrap().port()
         };
 
-        let target_host = "127.0.0.1";
+        let listen_endpoint = Endpoint::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: proxy_port,
+        };
+        let target_endpoint = Endpoint::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: echo_port,
+        };
+        let router = Router::new(target_endpoint);
 
         let is_running_clone = is_running.clone();
-        let shutdown_notify_clone = shutdown_notify.clone();
+        let shutdown_clone = shutdown.clone();
 
         tokio::spawn(async move {
             if let Err(e) = start_http_proxy(
-                target_host,
-                echo_port,
-                proxy_port,
+                listen_endpoint,
+                router,
+                ProxyConfig::default(),
                 is_running_clone,
-                shutdown_notify_clone,
+                shutdown_clone,
             )
             .await
             {
@@ -275,10 +900,192 @@ mod tests {
         assert_eq!(message.as_bytes(), &buffer[..]);
 
         is_running.store(false, Ordering::SeqCst);
-        shutdown_notify.notify_waiters();
+        shutdown.cancel();
 
         shutdown_sender.send(true).unwrap();
 
         time::sleep(Duration::from_secs(1)).await;
     }
+
+    /// Builds a minimal valid TLS ClientHello record containing a single
+    /// `server_name` extension, for exercising `parse_sni_host`.
+    fn build_client_hello(host: &str) -> Vec<u8> {
+        let host_bytes = host.as_bytes();
+
+        let mut server_name_ext = Vec::new();
+        let server_name_entry_len = 1 + 2 + host_bytes.len();
+        server_name_ext.extend_from_slice(&(server_name_entry_len as u16).to_be_bytes());
+        server_name_ext.push(0x00); // name type: host_name
+        server_name_ext.extend_from_slice(&(host_bytes.len() as u16).to_be_bytes());
+        server_name_ext.extend_from_slice(host_bytes);
+
+        let mut extensions = Vec::new();
+        extensions.extend_from_slice(&0x0000u16.to_be_bytes()); // extension type: server_name
+        extensions.extend_from_slice(&(server_name_ext.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&server_name_ext);
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&[0x03, 0x03]); // client_version
+        body.extend_from_slice(&[0u8; 32]); // random
+        body.push(0); // session_id length
+        body.extend_from_slice(&2u16.to_be_bytes()); // cipher_suites length
+        body.extend_from_slice(&[0x00, 0x2f]);
+        body.push(1); // compression_methods length
+        body.push(0x00);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = Vec::new();
+        handshake.push(0x01); // handshake type: client_hello
+        let body_len = (body.len() as u32).to_be_bytes();
+        handshake.extend_from_slice(&body_len[1..]); // 3-byte length
+
+        handshake.extend_from_slice(&body);
+
+        let mut record = Vec::new();
+        record.push(0x16); // content type: handshake
+        record.extend_from_slice(&[0x03, 0x01]); // record version
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+
+        record
+    }
+
+    #[test]
+    fn test_parse_sni_host_extracts_server_name() {
+        let hello = build_client_hello("Example.COM");
+        assert_eq!(parse_sni_host(&hello).as_deref(), Some("Example.COM"));
+    }
+
+    #[test]
+    fn test_parse_sni_host_handles_truncated_client_hello() {
+        let hello = build_client_hello("example.com");
+        let truncated = &hello[..hello.len() - 5];
+        assert_eq!(parse_sni_host(truncated), None);
+    }
+
+    #[test]
+    fn test_parse_http_host_matches_header_name_case_insensitively() {
+        let request = b"GET / HTTP/1.1\r\nHOST: Example.COM\r\nAccept: */*\r\n\r\n";
+        assert_eq!(parse_http_host(request).as_deref(), Some("Example.COM"));
+    }
+
+    #[test]
+    fn test_parse_http_host_returns_none_without_host_header() {
+        let request = b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n";
+        assert_eq!(parse_http_host(request), None);
+    }
+
+    #[test]
+    fn test_router_resolve_matches_routes_case_insensitively() {
+        let router = Router::new(Endpoint::Tcp {
+            host: "default.invalid".to_string(),
+            port: 1,
+        })
+        .with_route(
+            "api.example.com",
+            Endpoint::Tcp {
+                host: "backend.invalid".to_string(),
+                port: 2,
+            },
+        );
+
+        let resolved = router.resolve(Some("API.Example.com"));
+        assert!(matches!(
+            resolved,
+            Some(Endpoint::Tcp { host, .. }) if host == "backend.invalid"
+        ));
+    }
+
+    #[test]
+    fn test_router_resolve_falls_back_to_default() {
+        let router = Router::new(Endpoint::Tcp {
+            host: "default.invalid".to_string(),
+            port: 1,
+        });
+
+        let resolved = router.resolve(Some("unknown.example.com"));
+        assert!(matches!(
+            resolved,
+            Some(Endpoint::Tcp { host, .. }) if host == "default.invalid"
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_shutdown_drains_in_flight_transfer() {
+        // A backend that streams a multi-chunk response slowly enough
+        // that shutdown fires while the transfer is still in flight.
+        let backend_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = backend_listener.local_addr().unwrap().port();
+        let chunks: &[&[u8]] = &[b"chunk-one;", b"chunk-two;", b"chunk-three;"];
+
+        tokio::spawn(async move {
+            if let Ok((mut socket, _)) = backend_listener.accept().await {
+                let mut request = [0u8; 64];
+                let _ = socket.read(&mut request).await;
+                for chunk in chunks {
+                    socket.write_all(chunk).await.unwrap();
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        });
+
+        let is_running = Arc::new(AtomicBool::new(true));
+        let shutdown = CancellationToken::new();
+        let proxy_port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+
+        let listen_endpoint = Endpoint::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: proxy_port,
+        };
+        let router = Router::new(Endpoint::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: echo_port,
+        });
+        let config = ProxyConfig {
+            drain_deadline: Duration::from_secs(2),
+            ..ProxyConfig::default()
+        };
+
+        let is_running_clone = is_running.clone();
+        let shutdown_clone = shutdown.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = start_http_proxy(
+                listen_endpoint,
+                router,
+                config,
+                is_running_clone,
+                shutdown_clone,
+            )
+            .await
+            {
+                eprintln!("HTTP Proxy failed: {:?}", e);
+            }
+        });
+
+        time::sleep(Duration::from_millis(200)).await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        // A complete HTTP request lets sniffing finish on the first read, so
+        // the backend is dialed right away rather than after the idle timeout.
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: backend\r\n\r\n")
+            .await
+            .unwrap();
+
+        // Fire shutdown after the backend has started streaming but
+        // before it has sent every chunk.
+        time::sleep(Duration::from_millis(150)).await;
+        is_running.store(false, Ordering::SeqCst);
+        shutdown.cancel();
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, chunks.concat());
+    }
 }